@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use serde::{Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::OnceCell;
 use tokio::time::timeout;
@@ -9,49 +10,85 @@ use hyper::{
         header::CONTENT_TYPE,
         client::HttpConnector,
         Request,
+        StatusCode,
         self
 };
-use hyper_tls::HttpsConnector;
+use hyper_rustls::HttpsConnector;
+use rand::Rng;
+
+use crate::google::GoogleSecretManager;
+use crate::tls;
 
 
 const API_ENDPOINT: &str = "https://app.posthog.com/";
 const APT_CAPTURE: &str = "capture/";
+const APT_BATCH: &str = "batch/";
 const TIMEOUT: Duration = Duration::from_millis(2000);
 const POSTHOG_ENV: &str = "POSTHOG_API_KEY";
+const GCP_PROJECT_ENV: &str = "GOOGLE_CLOUD_PROJECT";
+const DEFAULT_MAX_BATCH_SIZE: usize = 500;
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(10);
 
+async fn init_hyper_client(
+    options: ApiOptions,
+) -> Result<hyper::Client<HttpsConnector<HttpConnector>>> {
+    let connector = tls::build_connector(&options)?;
 
-static HYPER_CLIENT: OnceCell<hyper::Client<HttpsConnector<HttpConnector>>> = OnceCell::const_new();
-
-async fn init_hyper_client() -> hyper::Client<HttpsConnector<HttpConnector>> {
-    let https = HttpsConnector::new();
-
-    hyper::client::Client::builder().build::<_, hyper::Body>(https)
+    Ok(hyper::client::Client::builder().build::<_, hyper::Body>(connector))
 }
 
 #[derive(Debug, Clone)]
 pub struct ApiOptions {
     endpoint: String,
     key: String,
+    ca_bundle: Option<Vec<u8>>,
+    pinned_fingerprint: Option<[u8; 32]>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Client {
     options: ApiOptions,
     timeout: Duration,
+    max_batch_size: usize,
+    retry_policy: RetryPolicy,
+    http_client: Arc<OnceCell<hyper::Client<HttpsConnector<HttpConnector>>>>,
 }
 
-#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+/// Controls how `Client` retries transient failures (connection errors,
+/// timeouts, and HTTP 5xx/429 responses). Attempt `i` (0-indexed) sleeps
+/// `min(max_delay, base_delay * 2^i)` plus uniform jitter in `[0, that_value]`
+/// before the next attempt.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
 pub struct Event {
     event: String,
     properties: Properties,
     timestamp: Option<chrono::NaiveDateTime>,
 }
 
-#[derive(serde::Serialize, Debug, PartialEq, Eq, Clone)]
+#[derive(serde::Serialize, Debug, PartialEq, Clone)]
 pub struct Properties {
     distinct_id: String,
     #[serde(flatten)]
-    properties: HashMap<String, String>,
+    properties: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Debug)]
@@ -62,9 +99,46 @@ struct InnerEvent {
     timestamp: Option<chrono::NaiveDateTime>,
 }
 
+#[derive(Serialize, Debug)]
+struct InnerBatch {
+    api_key: String,
+    batch: Vec<InnerBatchEvent>,
+}
+
+#[derive(Serialize, Debug)]
+struct InnerBatchEvent {
+    event: String,
+    properties: Properties,
+    timestamp: Option<chrono::NaiveDateTime>,
+}
+
 impl ApiOptions {
     pub fn new(endpoint: String, key: String) -> ApiOptions {
-        ApiOptions { endpoint, key }
+        ApiOptions { endpoint, key, ca_bundle: None, pinned_fingerprint: None }
+    }
+
+    /// Trusts the given PEM-encoded CA bundle instead of the system roots,
+    /// for PostHog instances behind a private CA. Ignored if a pinned
+    /// fingerprint is also configured.
+    pub fn with_ca_bundle(mut self, ca_bundle: Vec<u8>) -> ApiOptions {
+        self.ca_bundle = Some(ca_bundle);
+        self
+    }
+
+    /// Accepts the server's TLS certificate only when its leaf SHA-256
+    /// fingerprint matches exactly, bypassing the system trust chain
+    /// entirely. Takes priority over a configured CA bundle.
+    pub fn with_pinned_fingerprint(mut self, fingerprint: [u8; 32]) -> ApiOptions {
+        self.pinned_fingerprint = Some(fingerprint);
+        self
+    }
+
+    pub fn ca_bundle(&self) -> Option<&[u8]> {
+        self.ca_bundle.as_deref()
+    }
+
+    pub fn pinned_fingerprint(&self) -> Option<&[u8; 32]> {
+        self.pinned_fingerprint.as_ref()
     }
 
     pub fn from_env() -> Result<ApiOptions> {
@@ -101,55 +175,169 @@ impl ApiOptions {
         Ok(ApiOptions::new(API_ENDPOINT.to_string(), key))
     }
 
+    pub async fn from_google_secret_manager(project: &str, secret: &str) -> Result<ApiOptions> {
+        let manager = GoogleSecretManager::new().await?;
+        let data = manager.get_secret(project, secret).await?;
+        let key = String::from_utf8(data)?.trim().to_string();
+
+        assert!(!key.is_empty());
+
+        Ok(ApiOptions::new(API_ENDPOINT.to_string(), key))
+    }
+
+    /// Tries each secret backend in order, returning the first one that succeeds.
+    /// Defaults to env -> AWS Secrets Manager -> GCP Secret Manager; use
+    /// [`ApiOptions::auto_with_order`] to customize the order or omit backends.
     pub async fn auto(secret: &str) -> Result<ApiOptions> {
-        match ApiOptions::from_env() {
-            Ok(options) => Ok(options),
-            Err(_) => match ApiOptions::from_aws_secret_manager(secret).await {
-                Ok(options) => Ok(options),
-                Err(e) => Err(e),
-            },
+        ApiOptions::auto_with_order(
+            secret,
+            &[SecretBackend::Env, SecretBackend::Aws, SecretBackend::Gcp],
+        )
+        .await
+    }
+
+    pub async fn auto_with_order(secret: &str, order: &[SecretBackend]) -> Result<ApiOptions> {
+        let mut last_err = None;
+
+        for backend in order {
+            let result = match backend {
+                SecretBackend::Env => ApiOptions::from_env(),
+                SecretBackend::Aws => ApiOptions::from_aws_secret_manager(secret).await,
+                SecretBackend::Gcp => match std::env::var(GCP_PROJECT_ENV) {
+                    Ok(project) => ApiOptions::from_google_secret_manager(&project, secret).await,
+                    Err(e) => Err(anyhow!(e)),
+                },
+            };
+
+            match result {
+                Ok(options) => return Ok(options),
+                Err(e) => last_err = Some(e),
+            }
         }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("no secret backend configured")))
     }
 }
 
+/// A source `ApiOptions::auto` can pull the PostHog API key from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretBackend {
+    Env,
+    Aws,
+    Gcp,
+}
+
 impl Client {
     pub fn new(options: ApiOptions) -> Client {
-        Client { options , timeout: TIMEOUT}
+        Client {
+            options,
+            timeout: TIMEOUT,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            retry_policy: RetryPolicy::default(),
+            http_client: Arc::new(OnceCell::new()),
+        }
     }
 
     pub async fn new_with_timeout(options: ApiOptions, timeout: Duration) -> Client {
-        Client { options, timeout }
+        Client {
+            options,
+            timeout,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            retry_policy: RetryPolicy::default(),
+            http_client: Arc::new(OnceCell::new()),
+        }
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Client {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Client {
+        self.retry_policy = retry_policy;
+        self
     }
 
     pub async fn capture(&self, event: Event) -> Result<()> {
-        let client = HYPER_CLIENT.get_or_init(init_hyper_client).await;
         let inner_event = InnerEvent::new(event, self.options.key.clone());
         let url = format!("{}{}", self.options.endpoint, APT_CAPTURE);
+        let body = serde_json::to_string(&inner_event)?;
 
-        let request = Request::builder()
-            .method("POST")
-            .uri(url)
-            .header(CONTENT_TYPE, "application/json")
-            .body(hyper::Body::from(serde_json::to_string(&inner_event)?))?;
-
-        let future = client.request(request);
-        let _response = match timeout(self.timeout, future).await {
-            Ok(response) => response,
-            Err(e) => {
-                return Err(anyhow::anyhow!("Error: {}", e));
-            }
-        };
+        self.send_with_retry(&url, body).await
+    }
 
+    /// Sends events to PostHog's `/batch/` endpoint, splitting `events` into
+    /// chunks of at most `max_batch_size` so a single oversized call can't be
+    /// rejected or time out.
+    pub async fn capture_batch(&self, events: Vec<Event>) -> Result<()> {
+        for chunk in events.chunks(self.max_batch_size.max(1)) {
+            self.send_batch(chunk).await?;
+        }
 
         Ok(())
     }
 
-    pub async fn capture_batch(&self, events: Vec<Event>) -> Result<()> {
-        for event in events {
-            self.capture(event).await?;
+    async fn send_batch(&self, events: &[Event]) -> Result<()> {
+        let inner_batch = InnerBatch::new(events, self.options.key.clone());
+        let url = format!("{}{}", self.options.endpoint, APT_BATCH);
+        let body = serde_json::to_string(&inner_batch)?;
+
+        self.send_with_retry(&url, body).await
+    }
+
+    /// POSTs `body` to `url`, retrying connection errors, timeouts, and HTTP
+    /// 5xx/429 responses per `self.retry_policy`. Other 4xx responses fail
+    /// immediately without consuming a retry.
+    async fn send_with_retry(&self, url: &str, body: String) -> Result<()> {
+        let client = self
+            .http_client
+            .get_or_try_init(|| init_hyper_client(self.options.clone()))
+            .await?;
+        let mut last_err = None;
+
+        for attempt in 0..self.retry_policy.max_attempts {
+            let request = Request::builder()
+                .method("POST")
+                .uri(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(body.clone()))?;
+
+            match timeout(self.timeout, client.request(request)).await {
+                Ok(Ok(response)) => {
+                    let status = response.status();
+                    if status.is_success() {
+                        return Ok(());
+                    }
+
+                    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                        last_err = Some(anyhow!("posthog returned {status}"));
+                    } else {
+                        return Err(anyhow!("posthog returned {status}"));
+                    }
+                }
+                Ok(Err(e)) => last_err = Some(anyhow!("request error: {e}")),
+                Err(e) => last_err = Some(anyhow!("timeout: {e}")),
+            }
+
+            if attempt + 1 < self.retry_policy.max_attempts {
+                self.backoff(attempt).await;
+            }
         }
 
-        Ok(())
+        Err(last_err.unwrap_or_else(|| anyhow!("exhausted retries")))
+    }
+
+    async fn backoff(&self, attempt: u32) {
+        let delay = self
+            .retry_policy
+            .base_delay
+            .checked_mul(1u32 << attempt.min(31))
+            .unwrap_or(self.retry_policy.max_delay)
+            .min(self.retry_policy.max_delay);
+
+        let jitter = Duration::from_secs_f64(rand::thread_rng().gen::<f64>() * delay.as_secs_f64());
+
+        tokio::time::sleep(delay + jitter).await;
     }
 }
 
@@ -172,6 +360,39 @@ impl Event {
         });
     }
 
+    /// Inserts a property that can be any JSON value (number, bool, array,
+    /// object, ...), so it lands in PostHog as something insights can
+    /// actually aggregate and filter on, rather than as a string.
+    pub fn insert_prop_json<T: Into<String>>(&mut self, key: T, value: serde_json::Value) {
+        self.properties.insert_value(key.into(), value);
+    }
+
+    /// Inserts an integer property. `serde_json::Number` has no `From` impl
+    /// for `f32`/`f64` (it can't represent NaN/infinity), so this only
+    /// accepts integer types; use `insert_prop_float` for floating-point
+    /// values.
+    pub fn insert_prop_number<T: Into<String>, N: Into<serde_json::Number>>(&mut self, key: T, value: N) {
+        self.properties
+            .insert_value(key.into(), serde_json::Value::Number(value.into()));
+    }
+
+    /// Inserts a floating-point property. Fails if `value` is NaN or
+    /// infinite, since PostHog properties can't represent those.
+    pub fn insert_prop_float<T: Into<String>>(&mut self, key: T, value: f64) -> Result<()> {
+        let number = serde_json::Number::from_f64(value)
+            .ok_or_else(|| anyhow!("cannot insert non-finite float property: {value}"))?;
+
+        self.properties
+            .insert_value(key.into(), serde_json::Value::Number(number));
+
+        Ok(())
+    }
+
+    pub fn insert_prop_bool<T: Into<String>>(&mut self, key: T, value: bool) {
+        self.properties
+            .insert_value(key.into(), serde_json::Value::Bool(value));
+    }
+
     pub fn set_timestamp(&mut self, timestamp: chrono::NaiveDateTime) {
         self.timestamp = Some(timestamp);
     }
@@ -188,6 +409,29 @@ impl InnerEvent {
     }
 }
 
+impl InnerBatch {
+    pub fn new(events: &[Event], api_key: String) -> InnerBatch {
+        InnerBatch {
+            api_key,
+            batch: events
+                .iter()
+                .cloned()
+                .map(InnerBatchEvent::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<Event> for InnerBatchEvent {
+    fn from(event: Event) -> InnerBatchEvent {
+        InnerBatchEvent {
+            event: event.event.to_lowercase(),
+            properties: event.properties,
+            timestamp: event.timestamp,
+        }
+    }
+}
+
 impl Properties {
     pub fn new(distinct_id: String) -> Properties {
         Properties {
@@ -198,6 +442,10 @@ impl Properties {
     }
 
     pub fn insert(&mut self, key: String, value: String) {
+        self.insert_value(key, serde_json::Value::String(value));
+    }
+
+    pub fn insert_value(&mut self, key: String, value: serde_json::Value) {
         self.properties.insert(key, value);
     }
 }
@@ -230,6 +478,38 @@ mod tests {
         assert_eq!(json, assert_json.parse::<serde_json::Value>().unwrap());
     }
 
+    #[test]
+    #[ignore]
+    fn inner_batch_serializes() {
+        let mut event = Event::new("event".to_string(), "distinct_id".to_string());
+        event.insert_prop("key".to_string(), "value".to_string());
+        let inner_batch = InnerBatch::new(&[event], "api_key".to_string());
+        let json = serde_json::to_value(&inner_batch).unwrap();
+        let assert_json = "{\"api_key\":\"api_key\",\"batch\":[{\"event\":\"event\",\"properties\":{\"distinct_id\":\"distinct_id\",\"key\":\"value\"},\"timestamp\":null}]}";
+        assert_eq!(json, assert_json.parse::<serde_json::Value>().unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn inner_event_serializes_typed_properties() {
+        let mut event = Event::new("event".to_string(), "distinct_id".to_string());
+        event.insert_prop_number("count", 3);
+        event.insert_prop_bool("is_active", true);
+        event.insert_prop_json("tags", serde_json::json!(["a", "b"]));
+        let inner_event = InnerEvent::new(event, "api_key".to_string());
+        let json = serde_json::to_value(&inner_event).unwrap();
+        let assert_json = "{\"api_key\":\"api_key\",\"event\":\"event\",\"properties\":{\"distinct_id\":\"distinct_id\",\"count\":3,\"is_active\":true,\"tags\":[\"a\",\"b\"]},\"timestamp\":null}";
+        assert_eq!(json, assert_json.parse::<serde_json::Value>().unwrap());
+    }
+
+    #[test]
+    fn insert_prop_float_rejects_non_finite() {
+        let mut event = Event::new("event".to_string(), "distinct_id".to_string());
+        assert!(event.insert_prop_float("latency_ms", 12.5).is_ok());
+        assert!(event.insert_prop_float("bad", f64::NAN).is_err());
+        assert!(event.insert_prop_float("bad", f64::INFINITY).is_err());
+    }
+
     #[tokio::test]
     async fn test_client_env() {
         let opts = ApiOptions::from_env();
@@ -252,4 +532,19 @@ mod tests {
         let client = Client::new(opts);
         test_client(&client).await;
     }
+
+    #[tokio::test]
+    async fn test_client_google_secret_manager() {
+        let project = std::env::var("PROJECT").unwrap();
+        let secret = std::env::var("SECRET").unwrap();
+
+        let opts = ApiOptions::from_google_secret_manager(&project, &secret).await;
+        if opts.is_err() {
+            panic!("Error: {}", opts.err().unwrap());
+        }
+
+        let opts = opts.unwrap();
+        let client = Client::new(opts);
+        test_client(&client).await;
+    }
 }
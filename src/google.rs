@@ -20,11 +20,11 @@ impl GoogleSecretManager {
         let service_account_path = get_service_account_path().await?;
         let service_account_key = read_service_account_key(&service_account_path)
             .await
-            .expect("failed to read service account key");
+            .map_err(|e| anyhow!("failed to read service account key: {e}"))?;
         let auth = ServiceAccountAuthenticator::builder(service_account_key)
             .build()
             .await
-            .expect("failed to create authenticator");
+            .map_err(|e| anyhow!("failed to create authenticator: {e}"))?;
 
         Ok(GoogleSecretManager {
             client: SecretManager::new(
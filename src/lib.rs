@@ -0,0 +1,4 @@
+pub mod client;
+pub mod google;
+pub mod queue;
+mod tls;
@@ -0,0 +1,276 @@
+use crate::client::{Client, Event};
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+
+const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+const DEFAULT_FLUSH_BATCH_SIZE: usize = 100;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const CONTROL_CHANNEL_CAPACITY: usize = 8;
+
+/// What `QueuedClient::capture` does when the queue is already at capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Evict the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Wait for the background task to free up space before enqueueing.
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedClientOptions {
+    pub queue_capacity: usize,
+    pub flush_batch_size: usize,
+    pub flush_interval: Duration,
+    pub backpressure: BackpressurePolicy,
+}
+
+impl Default for QueuedClientOptions {
+    fn default() -> QueuedClientOptions {
+        QueuedClientOptions {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            flush_batch_size: DEFAULT_FLUSH_BATCH_SIZE,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            backpressure: BackpressurePolicy::Block,
+        }
+    }
+}
+
+enum Command {
+    Flush(oneshot::Sender<Result<()>>),
+    Shutdown(oneshot::Sender<Result<()>>),
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<Event>>,
+    capacity: usize,
+    backpressure: BackpressurePolicy,
+    space_available: Notify,
+    event_queued: Notify,
+}
+
+/// Buffers events in memory and flushes them to PostHog on a background task,
+/// so `capture` never blocks on network I/O. Queued events are grouped into
+/// `Client::capture_batch` calls either once `flush_batch_size` events have
+/// accumulated or `flush_interval` elapses, whichever comes first.
+pub struct QueuedClient {
+    shared: Arc<Shared>,
+    control: mpsc::Sender<Command>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl QueuedClient {
+    pub fn new(client: Client) -> QueuedClient {
+        QueuedClient::with_options(client, QueuedClientOptions::default())
+    }
+
+    pub fn with_options(client: Client, options: QueuedClientOptions) -> QueuedClient {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::with_capacity(options.queue_capacity)),
+            capacity: options.queue_capacity,
+            backpressure: options.backpressure,
+            space_available: Notify::new(),
+            event_queued: Notify::new(),
+        });
+
+        let (control, control_rx) = mpsc::channel(CONTROL_CHANNEL_CAPACITY);
+        let worker = tokio::spawn(QueuedClient::run(
+            client,
+            shared.clone(),
+            options.flush_batch_size,
+            options.flush_interval,
+            control_rx,
+        ));
+
+        QueuedClient { shared, control, worker: Some(worker) }
+    }
+
+    /// Enqueues `event` and returns without waiting on the network.
+    pub async fn capture(&self, event: Event) -> Result<()> {
+        loop {
+            let mut queue = self.shared.queue.lock().await;
+
+            if queue.len() < self.shared.capacity {
+                queue.push_back(event);
+                drop(queue);
+                self.shared.event_queued.notify_one();
+                return Ok(());
+            }
+
+            match self.shared.backpressure {
+                BackpressurePolicy::DropOldest => {
+                    queue.pop_front();
+                    queue.push_back(event);
+                    drop(queue);
+                    self.shared.event_queued.notify_one();
+                    return Ok(());
+                }
+                BackpressurePolicy::Block => {
+                    // Register as a waiter before releasing the lock so a
+                    // `notify_waiters()` that lands in the gap between
+                    // `drop(queue)` and awaiting isn't missed.
+                    let notified = self.shared.space_available.notified();
+                    tokio::pin!(notified);
+                    notified.as_mut().enable();
+                    drop(queue);
+                    notified.await;
+                }
+            }
+        }
+    }
+
+    /// Drains and sends all currently queued events, waiting for the flush to
+    /// land before returning.
+    pub async fn flush(&self) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.control
+            .send(Command::Flush(reply))
+            .await
+            .map_err(|_| anyhow!("queue worker has stopped"))?;
+
+        result.await.map_err(|_| anyhow!("queue worker has stopped"))?
+    }
+
+    /// Flushes remaining events and waits for the background task to exit, so
+    /// callers can shut down cleanly without dropping buffered events.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.control
+            .send(Command::Shutdown(reply))
+            .await
+            .map_err(|_| anyhow!("queue worker has stopped"))?;
+
+        let flush_result = result.await.map_err(|_| anyhow!("queue worker has stopped"))?;
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.await;
+        }
+
+        flush_result
+    }
+
+    async fn run(
+        client: Client,
+        shared: Arc<Shared>,
+        flush_batch_size: usize,
+        flush_interval: Duration,
+        mut control: mpsc::Receiver<Command>,
+    ) {
+        let mut interval = tokio::time::interval(flush_interval);
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    // Failures are requeued by `drain_and_send`; a caller that
+                    // needs to observe them should use `flush()`, whose
+                    // `Result` surfaces the same error.
+                    let _ = QueuedClient::drain_and_send(&client, &shared, usize::MAX).await;
+                }
+                _ = shared.event_queued.notified() => {
+                    let queued = shared.queue.lock().await.len();
+                    if queued >= flush_batch_size {
+                        let _ = QueuedClient::drain_and_send(&client, &shared, flush_batch_size).await;
+                    }
+                }
+                cmd = control.recv() => {
+                    match cmd {
+                        Some(Command::Flush(reply)) => {
+                            let result = QueuedClient::drain_and_send(&client, &shared, usize::MAX).await;
+                            let _ = reply.send(result);
+                        }
+                        Some(Command::Shutdown(reply)) => {
+                            let result = QueuedClient::drain_and_send(&client, &shared, usize::MAX).await;
+                            let _ = reply.send(result);
+                            return;
+                        }
+                        None => return,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn drain_and_send(client: &Client, shared: &Shared, max_events: usize) -> Result<()> {
+        loop {
+            let batch = {
+                let mut queue = shared.queue.lock().await;
+                let n = max_events.min(queue.len());
+                queue.drain(..n).collect::<Vec<_>>()
+            };
+
+            shared.space_available.notify_waiters();
+
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            if let Err(e) = client.capture_batch(batch.clone()).await {
+                QueuedClient::requeue(shared, batch).await;
+                return Err(e);
+            }
+
+            if max_events != usize::MAX {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Puts undelivered events back at the front of the queue (oldest first)
+    /// so a failed send doesn't lose data, trimming from the back if that
+    /// would push the queue over capacity.
+    async fn requeue(shared: &Shared, batch: Vec<Event>) {
+        let mut queue = shared.queue.lock().await;
+
+        for event in batch.into_iter().rev() {
+            queue.push_front(event);
+        }
+
+        while queue.len() > shared.capacity {
+            queue.pop_back();
+        }
+
+        drop(queue);
+        shared.event_queued.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ApiOptions, RetryPolicy};
+
+    fn failing_client() -> Client {
+        let options = ApiOptions::new("http://127.0.0.1:1/".to_string(), "api_key".to_string());
+
+        Client::new(options).with_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        })
+    }
+
+    #[tokio::test]
+    async fn drain_and_send_requeues_on_failure() {
+        let client = failing_client();
+        let shared = Shared {
+            queue: Mutex::new(VecDeque::from(vec![Event::new(
+                "event".to_string(),
+                "distinct_id".to_string(),
+            )])),
+            capacity: 10,
+            backpressure: BackpressurePolicy::Block,
+            space_available: Notify::new(),
+            event_queued: Notify::new(),
+        };
+
+        let result = QueuedClient::drain_and_send(&client, &shared, usize::MAX).await;
+        assert!(result.is_err());
+
+        let queue = shared.queue.lock().await;
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Result};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::client::ApiOptions;
+
+/// Builds the `HttpsConnector` for `options`, honouring a pinned leaf
+/// certificate fingerprint or a custom CA bundle when configured, falling
+/// back to the system's native root store otherwise.
+pub fn build_connector(options: &ApiOptions) -> Result<HttpsConnector<HttpConnector>> {
+    let builder = HttpsConnectorBuilder::new();
+
+    let tls_config = if let Some(fingerprint) = options.pinned_fingerprint() {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(FingerprintVerifier {
+                fingerprint: *fingerprint,
+            }))
+            .with_no_client_auth()
+    } else if let Some(ca_bundle) = options.ca_bundle() {
+        let mut roots = RootCertStore::empty();
+        let mut reader = std::io::BufReader::new(ca_bundle.as_slice());
+        for cert in rustls_pemfile::certs(&mut reader)? {
+            roots.add(&Certificate(cert))?;
+        }
+
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    } else {
+        return Ok(builder.with_native_roots().https_or_http().enable_http1().enable_http2().build());
+    };
+
+    Ok(builder
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build())
+}
+
+/// Accepts a TLS connection only when the presented leaf certificate's
+/// SHA-256 fingerprint matches exactly, ignoring the system trust chain.
+/// Used for self-hosted PostHog instances behind a private CA or a
+/// self-signed certificate.
+struct FingerprintVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = Sha256::digest(&end_entity.0);
+
+        if digest.as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(anyhow!(
+                "server certificate fingerprint does not match pinned fingerprint"
+            ).to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(fingerprint: [u8; 32], leaf: &[u8]) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = FingerprintVerifier { fingerprint };
+        let server_name = ServerName::try_from("example.com").unwrap();
+
+        verifier.verify_server_cert(
+            &Certificate(leaf.to_vec()),
+            &[],
+            &server_name,
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn fingerprint_verifier_accepts_matching_digest() {
+        let leaf = b"pretend this is a DER certificate";
+        let fingerprint: [u8; 32] = Sha256::digest(leaf).into();
+
+        assert!(verify(fingerprint, leaf).is_ok());
+    }
+
+    #[test]
+    fn fingerprint_verifier_rejects_mismatching_digest() {
+        let leaf = b"pretend this is a DER certificate";
+        let wrong_fingerprint = [0u8; 32];
+
+        assert!(verify(wrong_fingerprint, leaf).is_err());
+    }
+
+    #[test]
+    fn build_connector_errors_on_invalid_pem() {
+        // Well-formed PEM framing around bytes that don't decode to a valid
+        // X.509 certificate, so `RootCertStore::add` rejects it.
+        let pem = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n";
+        let options = ApiOptions::new("https://example.com/".to_string(), "key".to_string())
+            .with_ca_bundle(pem.as_bytes().to_vec());
+
+        assert!(build_connector(&options).is_err());
+    }
+}